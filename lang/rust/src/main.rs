@@ -1,21 +1,27 @@
-use std::fs;
-use std::io::{self, Read};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use std::io::BufReader;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
 
-use hex;
 use clap::{App, Arg};
 use md5::Md5;
 use sha1::Sha1;
 use sha2::Sha256;
+use sha2::Sha512;
 use sha2::Digest;
-use sha1::Digest;
-use sha256:Digest;
-use md5::Digest;
+use digest::FixedOutput;
 use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use indicatif::{ProgressBar, ProgressStyle};
 use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use nix::sys::stat;
 
 #[derive(Serialize, Deserialize)]
 #[derive(Clone)]
@@ -30,11 +36,332 @@ struct FileData {
     md5: String,
     sha1: String,
     sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sampled_sha256: Option<String>,
+    /// Whether `sha256` above was computed from the whole file ("full"),
+    /// `sampled_sha256` was computed from sampled windows instead
+    /// ("sampled"), or the full hash was left for `find_duplicates` to fill
+    /// in lazily, only for files that turn out to need it ("deferred").
+    checksum_mode: String,
+    // Full POSIX stat fields (via `nix`), zeroed on non-Unix platforms.
+    device_id: u64,
+    inode: u64,
+    link_count: u64,
+    uid: u32,
+    gid: u32,
+    rdev: u64,
+    block_size: i64,
+    block_count: i64,
+    access_time: i64,
+    access_time_nsec: i64,
+    modify_time: i64,
+    modify_time_nsec: i64,
+    change_time: i64,
+    change_time_nsec: i64,
+    /// Subresource Integrity string, e.g. `sha256-<base64>`. Left unset for
+    /// an entry with `checksum_mode` `"deferred"` or `"sampled"`, since
+    /// computing it would force the full read `--find-duplicates`/`--sampled`
+    /// chose to avoid for that file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    integrity: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 struct OutputData {
     file_data: Vec<FileData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicates: Option<HashMap<String, Vec<String>>>,
+}
+
+/// Digest algorithm used to compute a Subresource Integrity string.
+#[derive(Clone, Copy)]
+enum IntegrityAlg {
+    Sha256,
+    Sha512,
+}
+
+impl IntegrityAlg {
+    fn prefix(self) -> &'static str {
+        match self {
+            IntegrityAlg::Sha256 => "sha256",
+            IntegrityAlg::Sha512 => "sha512",
+        }
+    }
+}
+
+/// Computes a Subresource Integrity string (`<alg>-<base64 digest>`) for a
+/// file, suitable for web tooling and package-lock style manifests. If the
+/// caller already has a full (non-sampled) sha256 hex digest for this file —
+/// `process_file` computes one in the same read pass as md5/sha1 whenever
+/// `alg` is `Sha256` — pass it as `precomputed_sha256` so the whole file
+/// doesn't have to be read off disk a second time.
+fn compute_integrity(
+    path: &Path,
+    alg: IntegrityAlg,
+    precomputed_sha256: Option<&str>,
+) -> io::Result<String> {
+    if let (IntegrityAlg::Sha256, Some(hex_digest)) = (alg, precomputed_sha256) {
+        let digest = hex::decode(hex_digest)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        return Ok(format!("{}-{}", alg.prefix(), base64::encode(digest)));
+    }
+
+    let file = fs::File::open(path)?;
+    let mut buf_reader = BufReader::new(file);
+    let mut buffer = [0; 1024];
+
+    let encoded = match alg {
+        IntegrityAlg::Sha256 => {
+            let mut context = Sha256::default();
+            loop {
+                let read_bytes = buf_reader.read(&mut buffer)?;
+                if read_bytes == 0 {
+                    break;
+                }
+                context.input(&buffer[..read_bytes]);
+            }
+            base64::encode(context.fixed_result())
+        }
+        IntegrityAlg::Sha512 => {
+            let mut context = Sha512::default();
+            loop {
+                let read_bytes = buf_reader.read(&mut buffer)?;
+                if read_bytes == 0 {
+                    break;
+                }
+                context.input(&buffer[..read_bytes]);
+            }
+            base64::encode(context.fixed_result())
+        }
+    };
+
+    Ok(format!("{}-{}", alg.prefix(), encoded))
+}
+
+/// Copies or hardlinks each entry with a full sha256 digest into a
+/// content-addressable store under `cas_dir`, sharded two levels deep
+/// (`<dir>/ab/cd/rest`) to avoid huge flat directories, and writes a
+/// sidecar `index.json` mapping original paths to their integrity keys.
+/// Entries scanned in `--sampled` mode (or left `checksum_mode == "deferred"`
+/// by `--find-duplicates` without a colliding partial hash) don't carry a
+/// full `sha256`, which isn't a safe CAS key, so such entries are hashed in
+/// full here instead.
+fn populate_cas(cas_dir: &str, entries: &[FileData]) -> io::Result<()> {
+    fs::create_dir_all(cas_dir)?;
+    let mut index: HashMap<String, String> = HashMap::new();
+
+    for entry in entries {
+        if entry.is_dir {
+            continue;
+        }
+
+        let hex = if !entry.sha256.is_empty() {
+            entry.sha256.clone()
+        } else {
+            hash_file(Path::new(&entry.file_path), HashMode::Full)?
+        };
+        let hex = hex.as_str();
+        let key = format!("sha256:{}", hex);
+        let shard_dir = Path::new(cas_dir).join(&hex[0..2]).join(&hex[2..4]);
+        fs::create_dir_all(&shard_dir)?;
+        let dest = shard_dir.join(&hex[4..]);
+
+        if !dest.exists() {
+            let src = Path::new(&entry.file_path);
+            if fs::hard_link(src, &dest).is_err() {
+                fs::copy(src, &dest)?;
+            }
+        }
+
+        index.insert(entry.file_path.clone(), key);
+    }
+
+    let index_json = serde_json::to_string_pretty(&index)?;
+    fs::write(Path::new(cas_dir).join("index.json"), index_json)?;
+
+    Ok(())
+}
+
+/// Which portion of a file a checksum was computed over.
+enum HashMode {
+    /// Only the first `PARTIAL_HASH_SIZE` bytes were read.
+    Partial,
+    /// The entire file was read.
+    Full,
+}
+
+/// Number of leading bytes read when computing a partial hash.
+const PARTIAL_HASH_SIZE: usize = 4096;
+
+/// Number of evenly-spaced windows read by the sampled checksum mode.
+const SAMPLE_COUNT: u64 = 10;
+
+/// Size of each window read by the sampled checksum mode.
+const SAMPLE_WINDOW_SIZE: usize = 16 * 1024;
+
+/// Hashes `size` and `SAMPLE_COUNT` evenly-spaced `SAMPLE_WINDOW_SIZE`-byte
+/// windows spread across the file instead of its full contents, giving a
+/// near-instant fingerprint for very large files. Stable across scans as
+/// long as the file's content and length don't change, but it is not a
+/// cryptographic digest of the whole file and must not be treated as one.
+fn sampled_hash(path: &Path, size: u64) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut context = Sha256::default();
+    let window = SAMPLE_WINDOW_SIZE as u64;
+    let last_offset = size.saturating_sub(window);
+
+    let mut buffer = vec![0u8; SAMPLE_WINDOW_SIZE];
+    for i in 0..SAMPLE_COUNT {
+        let offset = if SAMPLE_COUNT <= 1 {
+            0
+        } else {
+            last_offset * i / (SAMPLE_COUNT - 1)
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let mut total_read = 0;
+        while total_read < buffer.len() {
+            let read_bytes = file.read(&mut buffer[total_read..])?;
+            if read_bytes == 0 {
+                break;
+            }
+            total_read += read_bytes;
+        }
+        context.input(&buffer[..total_read]);
+    }
+    context.input(&size.to_le_bytes());
+
+    Ok(hex::encode(context.fixed_result()))
+}
+
+/// Computes the md5, sha1, and sha256 digests of the whole file in a single
+/// read pass. Shared by `process_file` (the normal, eager path) and
+/// `find_duplicates` (which calls this lazily, only for files a
+/// partial-hash collision says might actually be duplicates).
+fn compute_full_hashes(path: &Path) -> io::Result<(String, String, String)> {
+    let file = fs::File::open(path)?;
+    let mut buf_reader = BufReader::new(file);
+
+    let mut md5_context = Md5::default();
+    let mut sha1_context = Sha1::default();
+    let mut sha256_context = Sha256::default();
+
+    let mut buffer = [0; 1024];
+    loop {
+        let read_bytes = buf_reader.read(&mut buffer)?;
+        if read_bytes == 0 {
+            break;
+        }
+        md5_context.input(&buffer[..read_bytes]);
+        sha1_context.input(&buffer[..read_bytes]);
+        sha256_context.input(&buffer[..read_bytes]);
+    }
+
+    Ok((
+        hex::encode(md5_context.fixed_result()),
+        hex::encode(sha1_context.fixed_result()),
+        hex::encode(sha256_context.fixed_result()),
+    ))
+}
+
+/// Hashes either a fixed-size prefix or the whole file, depending on `mode`.
+fn hash_file(path: &Path, mode: HashMode) -> io::Result<String> {
+    let file = fs::File::open(path)?;
+    let mut buf_reader = BufReader::new(file);
+    let mut sha256_context = Sha256::default();
+
+    match mode {
+        HashMode::Partial => {
+            let mut buffer = vec![0u8; PARTIAL_HASH_SIZE];
+            let mut total_read = 0;
+            while total_read < buffer.len() {
+                let read_bytes = buf_reader.read(&mut buffer[total_read..])?;
+                if read_bytes == 0 {
+                    break;
+                }
+                total_read += read_bytes;
+            }
+            sha256_context.input(&buffer[..total_read]);
+        }
+        HashMode::Full => {
+            let mut buffer = [0; 1024];
+            loop {
+                let read_bytes = buf_reader.read(&mut buffer)?;
+                if read_bytes == 0 {
+                    break;
+                }
+                sha256_context.input(&buffer[..read_bytes]);
+            }
+        }
+    }
+
+    Ok(hex::encode(sha256_context.fixed_result()))
+}
+
+/// Finds duplicate files among already-scanned `files` using a three-tier
+/// hash staging: entries are first grouped by the `size` recorded during the
+/// main scan, only the members of a size-colliding group ever have a partial
+/// hash read off disk, and only the members of a partial-hash-colliding
+/// group ever have a full hash read off disk. That full hash is normally
+/// computed here for the first time: `process_file` leaves
+/// `checksum_mode == "deferred"` (and `md5`/`sha1`/`sha256` empty) for every
+/// non-sampled file when `--find-duplicates` is set, precisely so the
+/// expensive whole-file read only happens for files that make it this far.
+/// `files` is taken `&mut` so those results can be written back, letting the
+/// final report carry real digests instead of leaving them blank. Sampled
+/// entries (`sampled_sha256`) are kept in their own key namespace rather than
+/// compared against `sha256` values, since a sampled fingerprint is not a
+/// whole-file digest and mixing the two risks false-positive groups.
+///
+/// Returns the duplicate groups alongside the indices into `files` whose
+/// `checksum_mode`/digests were actually resolved from "deferred" to "full"
+/// by this call, so a caller persisting entries elsewhere (e.g. a `--stream`
+/// NDJSON file) knows which already-written entries now need rewriting.
+fn find_duplicates(files: &mut [FileData]) -> (HashMap<String, Vec<String>>, HashSet<usize>) {
+    let mut by_size: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (idx, data) in files.iter().enumerate() {
+        if !data.is_dir {
+            by_size.entry(data.size).or_default().push(idx);
+        }
+    }
+
+    let mut by_partial_hash: HashMap<String, Vec<usize>> = HashMap::new();
+    for candidates in by_size.values().filter(|group| group.len() > 1) {
+        for &idx in candidates {
+            if let Ok(partial_hash) = hash_file(Path::new(&files[idx].file_path), HashMode::Partial) {
+                by_partial_hash.entry(partial_hash).or_default().push(idx);
+            }
+        }
+    }
+
+    let mut duplicates: HashMap<String, Vec<String>> = HashMap::new();
+    let mut resolved: HashSet<usize> = HashSet::new();
+    for candidates in by_partial_hash.values().filter(|group| group.len() > 1) {
+        for &idx in candidates {
+            let full_hash = if let Some(sampled) = &files[idx].sampled_sha256 {
+                format!("sampled:{}", sampled)
+            } else {
+                if files[idx].sha256.is_empty() {
+                    let path = PathBuf::from(&files[idx].file_path);
+                    match compute_full_hashes(&path) {
+                        Ok((md5, sha1, sha256)) => {
+                            let data = &mut files[idx];
+                            data.md5 = md5;
+                            data.sha1 = sha1;
+                            data.sha256 = sha256;
+                            data.checksum_mode = "full".to_string();
+                            resolved.insert(idx);
+                        }
+                        Err(_) => continue,
+                    }
+                }
+                format!("sha256:{}", files[idx].sha256)
+            };
+            duplicates.entry(full_hash).or_default().push(files[idx].file_path.clone());
+        }
+    }
+
+    duplicates.retain(|_, paths| paths.len() > 1);
+    (duplicates, resolved)
 }
 
 fn main() -> io::Result<()> {
@@ -64,6 +391,75 @@ fn main() -> io::Result<()> {
                 .help("Output file path")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("find-duplicates")
+                .long("find-duplicates")
+                .help("Group scanned files by content using a partial-then-full hash staging"),
+        )
+        .arg(
+            Arg::with_name("sampled")
+                .long("sampled")
+                .help("Use a sampled checksum instead of a full hash for files above --sample-threshold"),
+        )
+        .arg(
+            Arg::with_name("sample-threshold")
+                .long("sample-threshold")
+                .value_name("BYTES")
+                .help("File size above which --sampled switches to sampled checksums [default: 1073741824]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Output encoding: json or messagepack [default: json]")
+                .takes_value(true)
+                .possible_values(["json", "messagepack"]),
+        )
+        .arg(
+            Arg::with_name("integrity")
+                .long("integrity")
+                .help("Record a Subresource Integrity string for each file in its `integrity` field (left unset for files left checksum_mode \"deferred\" by --find-duplicates or \"sampled\" by --sampled)"),
+        )
+        .arg(
+            Arg::with_name("integrity-alg")
+                .long("integrity-alg")
+                .value_name("ALG")
+                .help("Digest algorithm for --integrity: sha256 or sha512 [default: sha256]")
+                .takes_value(true)
+                .possible_values(["sha256", "sha512"]),
+        )
+        .arg(
+            Arg::with_name("dedupe-hardlinks")
+                .long("dedupe-hardlinks")
+                .help("Scan only one path per (device, inode) pair, keeping the lexicographically lowest path"),
+        )
+        .arg(
+            Arg::with_name("cas-dir")
+                .long("cas-dir")
+                .value_name("DIR")
+                .help("Populate a content-addressable store at DIR from the scanned files")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .help("Number of worker threads to hash with [default: rayon's global default]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stream")
+                .long("stream")
+                .help("Write one JSON object per line to the output file as each file finishes, instead of one JSON blob at the end"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .requires("stream")
+                .help("With --stream, skip files already recorded (by path + mod_time) in an existing output file"),
+        )
         .get_matches();
 
     let start_dir = matches
@@ -73,31 +469,287 @@ fn main() -> io::Result<()> {
 
     let scan_sub_dirs = matches.is_present("sub-dirs");
     let output_file = matches.value_of("output").unwrap_or("file_data.json");
+    let find_duplicates_enabled = matches.is_present("find-duplicates");
+
+    let sample_threshold = if matches.is_present("sampled") {
+        let threshold = matches
+            .value_of("sample-threshold")
+            .map(|value| value.parse().expect("sample-threshold must be a number"))
+            .unwrap_or(1_073_741_824);
+        Some(threshold)
+    } else {
+        None
+    };
+
+    let integrity_alg = if matches.is_present("integrity") {
+        match matches.value_of("integrity-alg").unwrap_or("sha256") {
+            "sha512" => Some(IntegrityAlg::Sha512),
+            _ => Some(IntegrityAlg::Sha256),
+        }
+    } else {
+        None
+    };
+
+    let stream_enabled = matches.is_present("stream");
+    let resume_enabled = matches.is_present("resume");
+
+    let dedupe_hardlinks_enabled = matches.is_present("dedupe-hardlinks");
 
     let mut files: Vec<PathBuf> = Vec::new();
     let file_data = Arc::new(Mutex::new(Vec::new()));
+    let errors: Arc<Mutex<Vec<(PathBuf, io::Error)>>> = Arc::new(Mutex::new(Vec::new()));
+
+    traverse_files(Path::new(&start_dir), &mut files, scan_sub_dirs)?;
+
+    if dedupe_hardlinks_enabled {
+        // Stat every path up front (single-threaded, before any parallel
+        // hashing starts) so the survivor of a shared (device, inode) pair
+        // is picked deterministically by path rather than by which thread
+        // happens to process it first.
+        let mut by_inode: HashMap<(u64, u64), Vec<PathBuf>> = HashMap::new();
+        for path in &files {
+            if let Ok(stat) = posix_stat(path) {
+                if stat.device_id != 0 || stat.inode != 0 {
+                    by_inode.entry((stat.device_id, stat.inode)).or_default().push(path.clone());
+                }
+            }
+        }
 
-    traverse_files(&Path::new(&start_dir), &mut files, scan_sub_dirs)?;
+        let mut skip: HashSet<PathBuf> = HashSet::new();
+        for group in by_inode.values() {
+            if group.len() > 1 {
+                let mut sorted = group.clone();
+                sorted.sort();
+                skip.extend(sorted.into_iter().skip(1));
+            }
+        }
 
-    files.par_iter().for_each(|path| {
-        if let Ok(data) = process_file(path) {
-            let mut file_data = file_data.lock().unwrap();
-            file_data.push(data);
+        if !skip.is_empty() {
+            eprintln!(
+                "Skipping {} path(s) sharing a (device, inode) pair with a lower-sorted path already kept",
+                skip.len()
+            );
         }
+
+        files.retain(|path| !skip.contains(path));
+    }
+
+    let mut resume_entries: Vec<FileData> = Vec::new();
+    if resume_enabled {
+        resume_entries = load_resume_entries(output_file)?;
+        let resume_set: HashSet<(String, SystemTime)> = resume_entries
+            .iter()
+            .map(|data| (data.file_path.clone(), data.mod_time))
+            .collect();
+        files.retain(|path| {
+            let key = path.metadata().and_then(|m| m.modified());
+            match key {
+                Ok(mod_time) => !resume_set.contains(&(path.to_str().unwrap().to_string(), mod_time)),
+                Err(_) => true,
+            }
+        });
+    }
+
+    let ndjson_writer: Option<Arc<Mutex<io::BufWriter<fs::File>>>> = if stream_enabled {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(resume_enabled)
+            .truncate(!resume_enabled)
+            .write(true)
+            .open(output_file)?;
+        Some(Arc::new(Mutex::new(io::BufWriter::new(file))))
+    } else {
+        None
+    };
+
+    let pool = match matches.value_of("jobs") {
+        Some(jobs) => {
+            let num_threads: usize = jobs.parse().expect("jobs must be a number");
+            ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .expect("failed to build thread pool")
+        }
+        None => ThreadPoolBuilder::new().build().expect("failed to build thread pool"),
+    };
+
+    let progress = ProgressBar::new(files.len() as u64);
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template("{bar:40.cyan/blue} {pos}/{len} files ({per_sec}, ETA {eta})")
+            .unwrap(),
+    );
+
+    let need_accumulation = !stream_enabled || find_duplicates_enabled || matches.value_of("cas-dir").is_some();
+
+    if need_accumulation && resume_enabled {
+        // Paths still in `files` are about to be (re)scanned fresh, so fold
+        // in only the prior entries for paths that won't be touched this
+        // run, or this run's result would sit alongside a stale duplicate.
+        let rescan_paths: HashSet<String> =
+            files.iter().map(|path| path.to_str().unwrap().to_string()).collect();
+        file_data.lock().unwrap().extend(
+            resume_entries
+                .into_iter()
+                .filter(|data| !rescan_paths.contains(&data.file_path)),
+        );
+    }
+
+    // Entries folded in above come from a prior run and are already on disk;
+    // everything `file_data` gains from here on is new to this run.
+    let prior_entry_count = file_data.lock().unwrap().len();
+
+    pool.install(|| {
+        files.par_iter().for_each(|path| {
+            match process_file(path, sample_threshold, integrity_alg, find_duplicates_enabled) {
+                Ok(data) => {
+                    // With `--find-duplicates`, `data` can still carry
+                    // `checksum_mode: "deferred"` and blank digests at this
+                    // point - the real hash only lands once `find_duplicates`
+                    // resolves it below. Writing that placeholder straight to
+                    // the NDJSON stream would persist it permanently (a
+                    // `--resume`d run would see the path+mod_time "already
+                    // scanned" and never revisit it), so defer the write
+                    // until the resolved entries are flushed after dedup.
+                    if let Some(writer) = &ndjson_writer {
+                        if !find_duplicates_enabled {
+                            if let Err(err) = write_ndjson_line(writer, &data) {
+                                errors.lock().unwrap().push((path.clone(), err));
+                            }
+                        }
+                    }
+
+                    if need_accumulation {
+                        file_data.lock().unwrap().push(data);
+                    }
+                }
+                Err(err) => {
+                    errors.lock().unwrap().push((path.clone(), err));
+                }
+            }
+            progress.inc(1);
+        });
     });
 
-    let output_data = OutputData {
-        file_data: file_data.lock().unwrap().clone(),
+    progress.finish_with_message("done");
+
+    // Run dedup before populating the CAS: `find_duplicates` resolves a real
+    // `sha256` for every colliding "deferred" entry, and `populate_cas` reuses
+    // whatever `sha256` is already there instead of hashing the file again.
+    // Doing it in the other order made every entry that turned out to
+    // collide get hashed in full twice.
+    let (duplicates, resolved_indices) = if find_duplicates_enabled {
+        let (duplicates, resolved) = find_duplicates(&mut file_data.lock().unwrap());
+        (Some(duplicates), resolved)
+    } else {
+        (None, HashSet::new())
     };
 
-    let json = serde_json::to_string_pretty(&output_data)?;
-    fs::write(output_file, json)?;
+    if let Some(cas_dir) = matches.value_of("cas-dir") {
+        populate_cas(cas_dir, &file_data.lock().unwrap())?;
+    }
+
+    if let Some(writer) = &ndjson_writer {
+        if find_duplicates_enabled {
+            // This run's own entries were never written during the scan
+            // loop above (so they always need flushing now), but entries
+            // folded in from a prior run were already persisted back then -
+            // rewrite one of those only if `find_duplicates` just resolved
+            // it from "deferred" to "full", or its stale placeholder would
+            // otherwise never get corrected.
+            let data = file_data.lock().unwrap();
+            for (idx, entry) in data.iter().enumerate() {
+                let is_new_this_run = idx >= prior_entry_count;
+                if is_new_this_run || resolved_indices.contains(&idx) {
+                    if let Err(err) = write_ndjson_line(writer, entry) {
+                        errors.lock().unwrap().push((PathBuf::from(&entry.file_path), err));
+                    }
+                }
+            }
+        }
+    }
+
+    let errors = errors.lock().unwrap();
+    if !errors.is_empty() {
+        eprintln!("Failed to process {} file(s):", errors.len());
+        for (path, err) in errors.iter() {
+            eprintln!("  {}: {}", path.display(), err);
+        }
+    }
+
+    if !stream_enabled {
+        let output_data = OutputData {
+            file_data: file_data.lock().unwrap().clone(),
+            duplicates,
+        };
+
+        match matches.value_of("format").unwrap_or("json") {
+            "messagepack" => {
+                let bytes = rmp_serde::to_vec(&output_data)
+                    .map_err(io::Error::other)?;
+                fs::write(output_file, bytes)?;
+            }
+            _ => {
+                let json = serde_json::to_string_pretty(&output_data)?;
+                fs::write(output_file, json)?;
+            }
+        }
+    } else if find_duplicates_enabled {
+        if let Some(duplicates) = &duplicates {
+            eprintln!("Found {} duplicate group(s):", duplicates.len());
+            for (hash, paths) in duplicates {
+                eprintln!("  {}: {:?}", hash, paths);
+            }
+        }
+    }
 
     println!("Done!");
 
     Ok(())
 }
 
+/// Serializes one entry to a single NDJSON line and flushes it, so a reader
+/// (or a crash) never sees a partially-written line sitting unflushed in the
+/// writer's buffer.
+fn write_ndjson_line(
+    writer: &Arc<Mutex<io::BufWriter<fs::File>>>,
+    data: &FileData,
+) -> io::Result<()> {
+    let line = serde_json::to_string(data)?;
+    let mut writer = writer.lock().unwrap();
+    writeln!(writer, "{}", line)?;
+    writer.flush()
+}
+
+/// Reads back an existing NDJSON output file, keeping only the most recent
+/// entry recorded for each path (a later line overwrites an earlier one for
+/// the same path). Used by a `--resume`d run both to find which (path,
+/// mod_time) pairs can skip re-hashing, and to fold previously recorded
+/// entries into this run's in-memory accumulation, so duplicate detection
+/// and `--cas-dir` see every file ever scanned, not just the ones touched
+/// this run.
+fn load_resume_entries(output_file: &str) -> io::Result<Vec<FileData>> {
+    let mut by_path: HashMap<String, FileData> = HashMap::new();
+
+    let file = match fs::File::open(output_file) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Ok(data) = serde_json::from_str::<FileData>(&line) {
+            by_path.insert(data.file_path.clone(), data);
+        }
+    }
+
+    Ok(by_path.into_values().collect())
+}
+
 fn traverse_files(
     dir: &Path,
     files: &mut Vec<PathBuf>,
@@ -117,7 +769,72 @@ fn traverse_files(
     Ok(())
 }
 
-fn process_file(path: &Path) -> io::Result<FileData> {
+/// The subset of `struct stat` fields we surface beyond what
+/// `std::fs::Metadata` already exposes.
+struct PosixStat {
+    device_id: u64,
+    inode: u64,
+    link_count: u64,
+    uid: u32,
+    gid: u32,
+    rdev: u64,
+    block_size: i64,
+    block_count: i64,
+    access_time: i64,
+    access_time_nsec: i64,
+    modify_time: i64,
+    modify_time_nsec: i64,
+    change_time: i64,
+    change_time_nsec: i64,
+}
+
+#[cfg(unix)]
+fn posix_stat(path: &Path) -> io::Result<PosixStat> {
+    let stat = stat::stat(path).map_err(|errno| io::Error::from_raw_os_error(errno as i32))?;
+    Ok(PosixStat {
+        device_id: stat.st_dev as u64,
+        inode: stat.st_ino as u64,
+        link_count: stat.st_nlink as u64,
+        uid: stat.st_uid,
+        gid: stat.st_gid,
+        rdev: stat.st_rdev as u64,
+        block_size: stat.st_blksize as i64,
+        block_count: stat.st_blocks as i64,
+        access_time: stat.st_atime,
+        access_time_nsec: stat.st_atime_nsec,
+        modify_time: stat.st_mtime,
+        modify_time_nsec: stat.st_mtime_nsec,
+        change_time: stat.st_ctime,
+        change_time_nsec: stat.st_ctime_nsec,
+    })
+}
+
+#[cfg(not(unix))]
+fn posix_stat(_path: &Path) -> io::Result<PosixStat> {
+    Ok(PosixStat {
+        device_id: 0,
+        inode: 0,
+        link_count: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        block_size: 0,
+        block_count: 0,
+        access_time: 0,
+        access_time_nsec: 0,
+        modify_time: 0,
+        modify_time_nsec: 0,
+        change_time: 0,
+        change_time_nsec: 0,
+    })
+}
+
+fn process_file(
+    path: &Path,
+    sample_threshold: Option<u64>,
+    integrity_alg: Option<IntegrityAlg>,
+    defer_full_hash: bool,
+) -> io::Result<FileData> {
     let metadata = path.metadata()?;
     let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
     let extension = path.extension().unwrap_or_default().to_str().unwrap().to_string();
@@ -126,38 +843,47 @@ fn process_file(path: &Path) -> io::Result<FileData> {
     let permissions = metadata.permissions().mode();
     #[cfg(not(unix))]
     let permissions = 0;
-    
+
     let size = metadata.len();
     let mod_time = metadata.modified()?;
+    let posix_stat = posix_stat(path)?;
 
-    let (md5, sha1, sha256) = if !is_dir {
-        let file = fs::File::open(&path)?;
-        let mut buf_reader = BufReader::new(file);
+    let use_sampled = !is_dir && sample_threshold.is_some_and(|threshold| size > threshold);
 
-        let mut md5_context = Md5::default();
-        let mut sha1_context = Sha1::default();
-        let mut sha256_context = Sha256::default();        
-
-        let mut buffer = [0; 1024];
+    let (md5, sha1, sha256, sampled_sha256, checksum_mode) = if use_sampled {
+        let sampled_sha256 = sampled_hash(path, size)?;
+        (String::new(), String::new(), String::new(), Some(sampled_sha256), "sampled".to_string())
+    } else if !is_dir && defer_full_hash {
+        // `--find-duplicates` is on: leave the full hash unread here and let
+        // `find_duplicates` compute it later, only for files that survive
+        // the size and partial-hash prefilters.
+        (String::new(), String::new(), String::new(), None, "deferred".to_string())
+    } else if !is_dir {
+        let (md5, sha1, sha256) = compute_full_hashes(path)?;
+        (md5, sha1, sha256, None, "full".to_string())
+    } else {
+        (String::new(), String::new(), String::new(), None, "full".to_string())
+    };
 
-        loop {
-            let read_bytes = buf_reader.read(&mut buffer)?;
-            if read_bytes == 0 {
-                break;
+    // `checksum_mode == "deferred"` means `--find-duplicates` deliberately
+    // left `sha256` blank so this file's full contents are only read if a
+    // partial-hash collision says it might actually be a duplicate, and
+    // `"sampled"` means `--sampled` deliberately read only a few windows
+    // instead of the whole file because it's above the size threshold.
+    // Computing an integrity string in either case would force exactly the
+    // full read that mode was chosen to avoid, for every such file - so
+    // leave `integrity` unset rather than reading the file a second (or,
+    // for most files, first) time just for this.
+    let integrity = if !is_dir && checksum_mode != "deferred" && checksum_mode != "sampled" {
+        match integrity_alg {
+            Some(alg) => {
+                let precomputed_sha256 = if sha256.is_empty() { None } else { Some(sha256.as_str()) };
+                Some(compute_integrity(path, alg, precomputed_sha256)?)
             }
-
-            md5_context.input(&buffer[..read_bytes]);
-            sha1_context.input(&buffer[..read_bytes]);
-            sha256_context.input(&buffer[..read_bytes]);            
+            None => None,
         }
-
-        let md5 = hex::encode(md5_context.fixed_result());
-        let sha1 = hex::encode(sha1_context.fixed_result());
-        let sha256 = hex::encode(sha256_context.fixed_result());        
-
-        (md5, sha1, sha256)
     } else {
-        (String::new(), String::new(), String::new())
+        None
     };
 
     Ok(FileData {
@@ -171,5 +897,180 @@ fn process_file(path: &Path) -> io::Result<FileData> {
         md5,
         sha1,
         sha256,
+        sampled_sha256,
+        checksum_mode,
+        device_id: posix_stat.device_id,
+        inode: posix_stat.inode,
+        link_count: posix_stat.link_count,
+        uid: posix_stat.uid,
+        gid: posix_stat.gid,
+        rdev: posix_stat.rdev,
+        block_size: posix_stat.block_size,
+        block_count: posix_stat.block_count,
+        access_time: posix_stat.access_time,
+        access_time_nsec: posix_stat.access_time_nsec,
+        modify_time: posix_stat.modify_time,
+        modify_time_nsec: posix_stat.modify_time_nsec,
+        change_time: posix_stat.change_time,
+        change_time_nsec: posix_stat.change_time_nsec,
+        integrity,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a minimal, otherwise-zeroed `FileData` for a regular file at
+    /// `file_path` with the given `size`, for tests that only care about the
+    /// hash-staging or resume fields.
+    fn test_file_data(file_path: &str, size: u64, checksum_mode: &str) -> FileData {
+        FileData {
+            file_path: file_path.to_string(),
+            file_name: Path::new(file_path)
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string(),
+            extension: String::new(),
+            size,
+            mod_time: SystemTime::UNIX_EPOCH,
+            is_dir: false,
+            permissions: 0,
+            md5: String::new(),
+            sha1: String::new(),
+            sha256: String::new(),
+            sampled_sha256: None,
+            checksum_mode: checksum_mode.to_string(),
+            device_id: 0,
+            inode: 0,
+            link_count: 0,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            block_size: 0,
+            block_count: 0,
+            access_time: 0,
+            access_time_nsec: 0,
+            modify_time: 0,
+            modify_time_nsec: 0,
+            change_time: 0,
+            change_time_nsec: 0,
+            integrity: None,
+        }
+    }
+
+    /// Creates a fresh scratch directory under `target/` for a test to write
+    /// files into, named after `label` so concurrently-run tests don't
+    /// collide.
+    fn scratch_dir(label: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("file_scanner_test_{}", label));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn find_duplicates_groups_identical_content_and_fills_in_deferred_hashes() {
+        let dir = scratch_dir("dedup");
+        let a = dir.join("a.txt");
+        let b = dir.join("b.txt");
+        let c = dir.join("c.txt");
+        fs::write(&a, b"same content").unwrap();
+        fs::write(&b, b"same content").unwrap();
+        fs::write(&c, b"different content").unwrap();
+
+        // Mirrors what `process_file` leaves behind when `--find-duplicates`
+        // is set: size is known, but the digests are blank and
+        // `checksum_mode` is "deferred".
+        let mut files = vec![
+            test_file_data(a.to_str().unwrap(), 12, "deferred"),
+            test_file_data(b.to_str().unwrap(), 12, "deferred"),
+            test_file_data(c.to_str().unwrap(), 18, "deferred"),
+        ];
+
+        let (duplicates, resolved) = find_duplicates(&mut files);
+
+        assert_eq!(duplicates.len(), 1);
+        let (_, mut paths) = duplicates.into_iter().next().unwrap();
+        paths.sort();
+        let mut expected = vec![a.to_str().unwrap().to_string(), b.to_str().unwrap().to_string()];
+        expected.sort();
+        assert_eq!(paths, expected);
+
+        // The two colliding files should have had their full hash resolved
+        // and written back, and their indices reported via `resolved` so a
+        // caller can rewrite any already-persisted NDJSON lines for them;
+        // the non-colliding one is left alone.
+        assert_eq!(resolved, HashSet::from([0usize, 1usize]));
+        for data in &files {
+            if data.file_path == c.to_str().unwrap() {
+                assert_eq!(data.checksum_mode, "deferred");
+                assert!(data.sha256.is_empty());
+            } else {
+                assert_eq!(data.checksum_mode, "full");
+                assert!(!data.sha256.is_empty());
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn find_duplicates_ignores_files_with_unique_size() {
+        let dir = scratch_dir("unique_size");
+        let a = dir.join("a.txt");
+        let b = dir.join("b.txt");
+        fs::write(&a, b"short").unwrap();
+        fs::write(&b, b"a fair bit longer").unwrap();
+
+        let mut files = vec![
+            test_file_data(a.to_str().unwrap(), 5, "deferred"),
+            test_file_data(b.to_str().unwrap(), 18, "deferred"),
+        ];
+
+        let (duplicates, resolved) = find_duplicates(&mut files);
+
+        // Sizes differ, so neither file should even have its partial hash
+        // read, let alone its full hash - both stay "deferred", and no
+        // index is reported as resolved.
+        assert!(duplicates.is_empty());
+        assert!(resolved.is_empty());
+        assert!(files.iter().all(|data| data.checksum_mode == "deferred"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_resume_entries_keeps_only_the_last_line_per_path() {
+        let dir = scratch_dir("resume");
+        let ndjson_path = dir.join("out.ndjson");
+
+        let stale = test_file_data("/scan/a.txt", 5, "deferred");
+        let resolved = test_file_data("/scan/a.txt", 5, "full");
+        let other = test_file_data("/scan/b.txt", 9, "full");
+
+        let contents = format!(
+            "{}\n{}\n{}\n",
+            serde_json::to_string(&stale).unwrap(),
+            serde_json::to_string(&resolved).unwrap(),
+            serde_json::to_string(&other).unwrap(),
+        );
+        fs::write(&ndjson_path, contents).unwrap();
+
+        let entries = load_resume_entries(ndjson_path.to_str().unwrap()).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        let a_entry = entries.iter().find(|data| data.file_path == "/scan/a.txt").unwrap();
+        assert_eq!(a_entry.checksum_mode, "full");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_resume_entries_returns_empty_for_missing_file() {
+        let entries = load_resume_entries("/nonexistent/path/does-not-exist.ndjson").unwrap();
+        assert!(entries.is_empty());
+    }
+}